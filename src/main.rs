@@ -1,45 +1,144 @@
 use std::{
-    cell::{Cell, UnsafeCell},
+    cell::{Cell, RefCell, UnsafeCell},
     ops::{Deref, DerefMut},
 };
+#[cfg(debug_assertions)]
+use std::panic::Location;
 
-pub struct EpochCell<T> {
-    epoch: Cell<u32>,
+// `T: ?Sized` lets the cell wrap slices and trait objects: `UnsafeCell<T>` and
+// the `*mut T` held by the guards already tolerate fat pointers. Because `val`
+// is the last field, `&EpochCell<[u8; 3]>` unsizes to `&EpochCell<[u8]>` (and
+// likewise `dyn Trait`) through the built-in reference coercion — no
+// `CoerceUnsized` impl is possible for an owned value, since the `UnsafeCell`
+// field would require `T: CoerceUnsized<U>`.
+//
+// Borrow tracking is a generation dispenser (`next`) plus a stack of the
+// generations that are currently live (`stack`). `borrow_mut` dispenses a
+// fresh generation, pushes it, and records it as the guard's `mark`; a guard
+// is *current* iff its `mark` is on top of the stack. Dropping a guard removes
+// its generation wherever it sits, so the generation beneath is re-exposed and
+// an outer guard re-validates even when guards are dropped out of LIFO order.
+// A `no_std` build would swap `RefCell<Vec<u64>>` for a fixed-capacity inline
+// array with the same push/remove-by-value contract.
+//
+// In debug builds each cell also remembers the source location of the most
+// recent `borrow_mut` (`invalidated_at`), and every guard remembers where it
+// was created (`created_at`), so a stale-borrow panic can point at both the
+// victim and the reborrow that invalidated it. These fields and the tracking
+// code are `cfg(debug_assertions)`-gated and compile out entirely in release.
+pub struct EpochCell<T: ?Sized> {
+    next: Cell<u64>,
+    stack: RefCell<Vec<u64>>,
+    #[cfg(debug_assertions)]
+    invalidated_at: Cell<Option<&'static Location<'static>>>,
     val: UnsafeCell<T>,
 }
 
-pub struct RefMut<'a, T> {
+pub struct RefMut<'a, T: ?Sized> {
     ptr: *mut T,
-    epoch: &'a Cell<u32>,
-    mark: u32,
+    stack: &'a RefCell<Vec<u64>>,
+    mark: u64,
+    #[cfg(debug_assertions)]
+    created_at: &'static Location<'static>,
+    #[cfg(debug_assertions)]
+    invalidated_at: &'a Cell<Option<&'static Location<'static>>>,
 }
 
-pub struct Ref<'a, T>(RefMut<'a, T>);
+pub struct Ref<'a, T: ?Sized> {
+    ptr: *const T,
+    stack: &'a RefCell<Vec<u64>>,
+    mark: u64,
+    #[cfg(debug_assertions)]
+    created_at: &'static Location<'static>,
+    #[cfg(debug_assertions)]
+    invalidated_at: &'a Cell<Option<&'static Location<'static>>>,
+}
 
 impl<T> EpochCell<T> {
     pub fn new(val: T) -> Self {
         EpochCell {
-            epoch: Cell::new(0u32),
+            // Generations start at 1 so that 0 can mean "ground" (empty stack)
+            // for shared borrows taken while no `borrow_mut` is live.
+            next: Cell::new(1u64),
+            stack: RefCell::new(Vec::new()),
+            #[cfg(debug_assertions)]
+            invalidated_at: Cell::new(None),
             val: UnsafeCell::new(val),
         }
     }
 
+    pub fn into_inner(self) -> T {
+        self.val.into_inner()
+    }
+
+    /// Sets the contained value, bumping the epoch like any `borrow_mut`.
+    pub fn set(&self, val: T) {
+        *self.borrow_mut() = val;
+    }
+
+    /// Replaces the contained value with `val` and returns the old value.
+    pub fn replace(&self, val: T) -> T {
+        std::mem::replace(&mut *self.borrow_mut(), val)
+    }
+
+    /// Swaps the values of two cells, taking a fresh `borrow_mut` on each so
+    /// both epochs are bumped. Swapping a cell with itself is a no-op (two live
+    /// `borrow_mut`s into one cell would alias), matching `Cell::swap`.
+    pub fn swap(&self, other: &EpochCell<T>) {
+        if std::ptr::eq(self, other) {
+            return;
+        }
+        std::mem::swap(&mut *self.borrow_mut(), &mut *other.borrow_mut());
+    }
+
+    /// Takes the contained value, leaving `Default::default()` in its place.
+    pub fn take(&self) -> T
+    where
+        T: Default,
+    {
+        self.replace(T::default())
+    }
+
+    /// Updates the contained value in place by applying `f` to it.
+    pub fn update<F: FnOnce(T) -> T>(&self, f: F)
+    where
+        T: Copy,
+    {
+        let mut g = self.borrow_mut();
+        *g = f(*g);
+    }
+}
+
+impl<T: ?Sized> EpochCell<T> {
+    #[track_caller]
     pub fn borrow(&self) -> Ref<'_, T> {
-        let cur = self.epoch.get();
-        Ref(RefMut {
+        let top = self.stack.borrow().last().copied().unwrap_or(0);
+        Ref {
             ptr: self.val.get(),
-            epoch: &self.epoch,
-            mark: cur,
-        })
+            stack: &self.stack,
+            mark: top,
+            #[cfg(debug_assertions)]
+            created_at: Location::caller(),
+            #[cfg(debug_assertions)]
+            invalidated_at: &self.invalidated_at,
+        }
     }
 
+    #[track_caller]
     pub fn borrow_mut(&self) -> RefMut<'_, T> {
-        let cur = self.epoch.get();
-        self.epoch.set(cur + 1);
+        let gen = self.next.get();
+        self.next.set(gen + 1);
+        self.stack.borrow_mut().push(gen);
+        #[cfg(debug_assertions)]
+        self.invalidated_at.set(Some(Location::caller()));
         RefMut {
             ptr: self.val.get(),
-            epoch: &self.epoch,
-            mark: cur,
+            stack: &self.stack,
+            mark: gen,
+            #[cfg(debug_assertions)]
+            created_at: Location::caller(),
+            #[cfg(debug_assertions)]
+            invalidated_at: &self.invalidated_at,
         }
     }
 
@@ -47,42 +146,174 @@ impl<T> EpochCell<T> {
     pub fn get_mut(&mut self) -> &'_ mut T {
         self.val.get_mut()
     }
+}
+
+impl<'a, T: ?Sized> RefMut<'a, T> {
+    /// Returns `true` while this guard is the top of the borrow stack, i.e. no
+    /// `borrow_mut` guard taken after it is still live.
+    pub fn is_current(&self) -> bool {
+        self.stack.borrow().last() == Some(&self.mark)
+    }
 
-    pub fn into_inner(self) -> T {
-        self.val.into_inner()
+    /// Like `Deref`, but returns `None` instead of panicking when the guard
+    /// has been made stale by a reborrow.
+    pub fn try_deref(&self) -> Option<&T> {
+        if self.is_current() {
+            Some(unsafe { self.ptr.as_ref().expect("nullptr") })
+        } else {
+            None
+        }
+    }
+
+    /// Like `DerefMut`, but returns `None` instead of panicking when the guard
+    /// has been made stale by a reborrow.
+    pub fn try_deref_mut(&mut self) -> Option<&mut T> {
+        if self.is_current() {
+            Some(unsafe { self.ptr.as_mut().expect("nullptr") })
+        } else {
+            None
+        }
+    }
+
+    /// Narrows the guard onto a sub-field or element, mirroring
+    /// [`std::cell::RefMut::map`]. The returned guard keeps the original's
+    /// `stack` reference and generation `mark`, so an inner `borrow_mut` after
+    /// the projection still makes it stale.
+    pub fn map<U: ?Sized, F: FnOnce(&mut T) -> &mut U>(orig: RefMut<'a, T>, f: F) -> RefMut<'a, U> {
+        let ptr = f(unsafe { orig.ptr.as_mut().expect("nullptr") }) as *mut U;
+        let stack = orig.stack;
+        let mark = orig.mark;
+        #[cfg(debug_assertions)]
+        let created_at = orig.created_at;
+        #[cfg(debug_assertions)]
+        let invalidated_at = orig.invalidated_at;
+        // Forget `orig` so it does not pop its generation; the mapped guard
+        // inherits ownership of that generation and removes it on drop.
+        std::mem::forget(orig);
+        RefMut {
+            ptr,
+            stack,
+            mark,
+            #[cfg(debug_assertions)]
+            created_at,
+            #[cfg(debug_assertions)]
+            invalidated_at,
+        }
+    }
+}
+
+impl<'a, T: ?Sized> Ref<'a, T> {
+    /// Returns `true` while this shared guard still reflects the current value,
+    /// i.e. the top of the borrow stack is unchanged since it was taken.
+    pub fn is_current(&self) -> bool {
+        self.stack.borrow().last().copied().unwrap_or(0) == self.mark
+    }
+
+    /// Like `Deref`, but returns `None` instead of panicking when the guard
+    /// has been made stale by a reborrow.
+    pub fn try_deref(&self) -> Option<&T> {
+        if self.is_current() {
+            Some(unsafe { self.ptr.as_ref().expect("nullptr") })
+        } else {
+            None
+        }
+    }
+
+    /// Narrows the guard onto a sub-field or element, mirroring
+    /// [`std::cell::Ref::map`]. The returned guard keeps the original's
+    /// `stack` reference and generation `mark`, so an inner `borrow_mut` after
+    /// the projection still makes it stale.
+    pub fn map<U: ?Sized, F: FnOnce(&T) -> &U>(orig: Ref<'a, T>, f: F) -> Ref<'a, U> {
+        let ptr = f(unsafe { orig.ptr.as_ref().expect("nullptr") }) as *const U;
+        Ref {
+            ptr,
+            stack: orig.stack,
+            mark: orig.mark,
+            #[cfg(debug_assertions)]
+            created_at: orig.created_at,
+            #[cfg(debug_assertions)]
+            invalidated_at: orig.invalidated_at,
+        }
     }
 }
 
-impl<'a, T> Drop for RefMut<'a, T> {
+impl<'a, T: ?Sized> Drop for RefMut<'a, T> {
     fn drop(&mut self) {
-        if self.epoch.get() == self.mark + 1 {
-            self.epoch.set(self.mark);
+        // Remove this guard's generation wherever it sits; removing the top
+        // re-exposes the generation beneath and re-validates the outer guard.
+        let mut stack = self.stack.borrow_mut();
+        if let Some(pos) = stack.iter().rposition(|&gen| gen == self.mark) {
+            stack.remove(pos);
         }
     }
 }
 
-impl<'a, T> Deref for RefMut<'a, T> {
+/// Panic helper for a stale guard access. In debug builds it names both the
+/// victim guard's creation site and the cell's most recent `borrow_mut`; that
+/// is the invalidating one unless a later guard has since been dropped, since
+/// only the latest call site is recorded. In release builds it degrades to the
+/// bare message with no recorded locations.
+#[cfg(debug_assertions)]
+#[cold]
+#[track_caller]
+fn stale_panic(
+    kind: &str,
+    created_at: &'static Location<'static>,
+    invalidated_at: &Cell<Option<&'static Location<'static>>>,
+) -> ! {
+    match invalidated_at.get() {
+        Some(by) => {
+            panic!("{kind}: invalidated by borrow_mut at {by} (guard created at {created_at})")
+        }
+        None => panic!("{kind} (guard created at {created_at})"),
+    }
+}
+
+#[cfg(not(debug_assertions))]
+#[cold]
+#[track_caller]
+fn stale_panic(kind: &str) -> ! {
+    panic!("{kind}");
+}
+
+impl<'a, T: ?Sized> Deref for RefMut<'a, T> {
     type Target = T;
     #[track_caller]
     fn deref(&self) -> &T {
-        assert_eq!(self.epoch.get(), self.mark + 1, "stale borrow");
+        if !self.is_current() {
+            #[cfg(debug_assertions)]
+            stale_panic("stale borrow", self.created_at, self.invalidated_at);
+            #[cfg(not(debug_assertions))]
+            stale_panic("stale borrow");
+        }
         unsafe { self.ptr.as_ref().expect("nullptr") }
     }
 }
 
-impl<'a, T> DerefMut for RefMut<'a, T> {
+impl<'a, T: ?Sized> DerefMut for RefMut<'a, T> {
     #[track_caller]
     fn deref_mut(&mut self) -> &mut T {
-        assert_eq!(self.epoch.get(), self.mark + 1, "stale mut borrow");
+        if !self.is_current() {
+            #[cfg(debug_assertions)]
+            stale_panic("stale mut borrow", self.created_at, self.invalidated_at);
+            #[cfg(not(debug_assertions))]
+            stale_panic("stale mut borrow");
+        }
         unsafe { self.ptr.as_mut().expect("nullptr") }
     }
 }
 
-impl<'a, T> Deref for Ref<'a, T> {
+impl<'a, T: ?Sized> Deref for Ref<'a, T> {
     type Target = T;
     #[track_caller]
     fn deref(&self) -> &T {
-        self.0.deref()
+        if !self.is_current() {
+            #[cfg(debug_assertions)]
+            stale_panic("stale borrow", self.created_at, self.invalidated_at);
+            #[cfg(not(debug_assertions))]
+            stale_panic("stale borrow");
+        }
+        unsafe { self.ptr.as_ref().expect("nullptr") }
     }
 }
 
@@ -186,7 +417,167 @@ mod tests {
         assert_eq!(c.into_inner(), vec![0u32, 2, 3]);
     }
 
+    #[test]
+    fn try_deref_stale() {
+        let c = EpochCell::new(7u32);
+        let a = c.borrow_mut();
+        assert!(a.is_current());
+        assert_eq!(a.try_deref(), Some(&7));
+        {
+            let _b = c.borrow_mut();
+            assert!(!a.is_current());
+            assert_eq!(a.try_deref(), None); // stale, but no panic
+        }
+        assert!(a.is_current());
+        assert_eq!(a.try_deref(), Some(&7));
+    }
+
+    #[test]
+    fn map_projects_field() {
+        let c = EpochCell::new((1u32, 2u32));
+        {
+            let mut g = RefMut::map(c.borrow_mut(), |t| &mut t.1);
+            assert_eq!(*g, 2);
+            *g = 42;
+        }
+        assert_eq!(c.into_inner(), (1, 42));
+    }
+
+    #[test]
+    #[should_panic]
+    fn map_keeps_epoch_invariant() {
+        let c = EpochCell::new((1u32, 2u32));
+        let g = RefMut::map(c.borrow_mut(), |t| &mut t.1);
+        let _inner = c.borrow_mut(); // invalidates the projected guard
+        let _ = *g; // panics: "stale borrow" from `Deref`
+    }
+
+    #[test]
+    fn value_ops() {
+        let c = EpochCell::new(1u32);
+        c.set(5);
+        assert_eq!(c.replace(9), 5);
+        c.update(|v| v + 1);
+        assert_eq!(c.take(), 10);
+        assert_eq!(c.into_inner(), 0);
+
+        let a = EpochCell::new(1u8);
+        let b = EpochCell::new(2u8);
+        a.swap(&b);
+        assert_eq!((a.into_inner(), b.into_inner()), (2, 1));
+
+        // self-swap is a no-op
+        let s = EpochCell::new(7u8);
+        s.swap(&s);
+        assert_eq!(s.into_inner(), 7);
+    }
+
+    #[test]
+    fn out_of_order_drop() {
+        let c = EpochCell::new(0u8);
+        {
+            let outer = c.borrow_mut();
+            let mut inner = c.borrow_mut();
+            drop(outer); // non-LIFO: inner is still the top and stays current
+            *inner = 7;
+            drop(inner);
+        }
+        // The borrow stack is empty again: a fresh borrow must not panic.
+        let r = c.borrow();
+        assert_eq!(*r, 7);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "invalidated by borrow_mut at")]
+    fn stale_panic_names_invalidator() {
+        let c = EpochCell::new(0u32);
+        let a = c.borrow_mut();
+        let _b = c.borrow_mut(); // records its call site as the invalidator
+        let _ = *a; // panics, pointing at `_b`'s borrow_mut
+    }
+
     /* 1. deep recursion pushes/pops 1 000 times */
+    #[test]
+    fn try_deref_stale() {
+        let c = EpochCell::new(7u32);
+        let a = c.borrow_mut();
+        assert!(a.is_current());
+        assert_eq!(a.try_deref(), Some(&7));
+        {
+            let _b = c.borrow_mut();
+            assert!(!a.is_current());
+            assert_eq!(a.try_deref(), None); // stale, but no panic
+        }
+        assert!(a.is_current());
+        assert_eq!(a.try_deref(), Some(&7));
+    }
+
+    #[test]
+    fn map_projects_field() {
+        let c = EpochCell::new((1u32, 2u32));
+        {
+            let mut g = RefMut::map(c.borrow_mut(), |t| &mut t.1);
+            assert_eq!(*g, 2);
+            *g = 42;
+        }
+        assert_eq!(c.into_inner(), (1, 42));
+    }
+
+    #[test]
+    #[should_panic]
+    fn map_keeps_epoch_invariant() {
+        let c = EpochCell::new((1u32, 2u32));
+        let g = RefMut::map(c.borrow_mut(), |t| &mut t.1);
+        let _inner = c.borrow_mut(); // invalidates the projected guard
+        let _ = *g; // panics: "stale borrow" from `Deref`
+    }
+
+    #[test]
+    fn value_ops() {
+        let c = EpochCell::new(1u32);
+        c.set(5);
+        assert_eq!(c.replace(9), 5);
+        c.update(|v| v + 1);
+        assert_eq!(c.take(), 10);
+        assert_eq!(c.into_inner(), 0);
+
+        let a = EpochCell::new(1u8);
+        let b = EpochCell::new(2u8);
+        a.swap(&b);
+        assert_eq!((a.into_inner(), b.into_inner()), (2, 1));
+
+        // self-swap is a no-op
+        let s = EpochCell::new(7u8);
+        s.swap(&s);
+        assert_eq!(s.into_inner(), 7);
+    }
+
+    #[test]
+    fn out_of_order_drop() {
+        let c = EpochCell::new(0u8);
+        {
+            let outer = c.borrow_mut();
+            let mut inner = c.borrow_mut();
+            drop(outer); // non-LIFO: inner is still the top and stays current
+            *inner = 7;
+            drop(inner);
+        }
+        // The borrow stack is empty again: a fresh borrow must not panic.
+        let r = c.borrow();
+        assert_eq!(*r, 7);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "invalidated by borrow_mut at")]
+    fn stale_panic_names_invalidator() {
+        let c = EpochCell::new(0u32);
+        let a = c.borrow_mut();
+        let _b = c.borrow_mut(); // records its call site as the invalidator
+        let _ = *a; // panics, pointing at `_b`'s borrow_mut
+    }
+
     fn recurse(cell: &EpochCell<u32>, depth: u32) {
         if depth == 0 {
             return;
@@ -261,11 +652,11 @@ mod tests {
         c.borrow(); // no panic
     }
 
-    /* 6. DST slice inside the cell
-     * TODO: make this work
+    /* 6. DST slice inside the cell */
     #[test]
     fn dst_slice() {
-        let c: EpochCell<[u8]> = EpochCell::new([1, 2, 3]);
+        let owned = EpochCell::new([1u8, 2, 3]);
+        let c: &EpochCell<[u8]> = &owned;
         {
             let mut g = c.borrow_mut();
             g[1] = 42;
@@ -277,5 +668,4 @@ mod tests {
         }
         assert_eq!(&*c.borrow(), &[1, 42, 99]);
     }
-    */
 }